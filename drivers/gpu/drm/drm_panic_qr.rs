@@ -3,9 +3,10 @@
 //! This is a simple qr encoder for DRM panic
 //! Due to the Panic constraint, it doesn't allocate memory and does all the work
 //! on the stack or on the provided buffers.
-//! For simplification, it only supports Low error correction, and apply the
-//! first mask (checkboard). It will draw the smallest QRcode that can contain
-//! the string passed as parameter.
+//! It supports the four error correction levels (Low, Medium, Quartile, High),
+//! selectable by the caller to trade capacity for robustness, and picks the
+//! data mask with the lowest ISO 18004 penalty. It will draw the smallest
+//! QRcode that can contain the string passed as parameter.
 //! To get the most compact QR-code, the start of the url is encoded as binary,
 //! and the compressed kmsg is encoded as numeric.
 //! The binary data must be a valid url parameter, so the easiest way is to use
@@ -16,6 +17,15 @@
 //! decimal digits, into 40bits in the QR-Code, so wasting only 2.5%.
 //! And numbers are valid url parameter, so the website can do the reverse, to
 //! get the binary data.
+//!
+//! When the payload doesn't fit in a single code, it is rendered as a
+//! structured-append sequence: the caller asks for one symbol at a time
+//! (`symbol` 0, 1, ...) and re-calls with the next index until rendering
+//! returns 0, rather than the encoder generating every symbol's framebuffer
+//! in one batched call. This keeps the entry points to one per payload kind
+//! (`qr_encode_txt`, `qr_encode_url`) with a single extra `symbol` parameter,
+//! instead of a second, parallel batch-generation API for the multi-symbol
+//! case.
 
 use core::cmp;
 use kernel::str::CStr;
@@ -25,12 +35,19 @@ const __LOG_PREFIX: &[u8] = b"rust_qrcode\0";
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Ord, PartialOrd)]
 struct Version(usize);
 
-// Generator polynomials for QR Code, only those that are needed for Low quality
+// Generator polynomials for QR Code, for all error correction levels
 const P7: [u8; 7] = [87, 229, 146, 149, 238, 102, 21];
 const P10: [u8; 10] = [251, 67, 46, 61, 118, 70, 64, 94, 32, 45];
+const P13: [u8; 13] = [74, 152, 176, 100, 86, 100, 106, 104, 130, 218, 206, 140, 78];
 const P15: [u8; 15] = [
     8, 183, 61, 91, 202, 37, 51, 58, 58, 237, 140, 124, 5, 99, 105,
 ];
+const P16: [u8; 16] = [
+    120, 104, 107, 109, 102, 161, 76, 3, 91, 191, 147, 169, 182, 194, 225, 120,
+];
+const P17: [u8; 17] = [
+    43, 139, 206, 78, 43, 239, 123, 206, 214, 147, 24, 99, 150, 39, 243, 163, 136,
+];
 const P18: [u8; 18] = [
     215, 234, 158, 94, 184, 97, 118, 170, 79, 187, 152, 148, 252, 179, 5, 98, 96, 153,
 ];
@@ -58,7 +75,32 @@ const P30: [u8; 30] = [
     224, 130, 156, 37, 251, 216, 238, 40, 192, 180,
 ];
 
-/// QRCode parameter for Low quality ECC:
+/// QR Code error correction level.
+///
+/// Higher levels add more redundancy, at the cost of data capacity. `Low`
+/// stores the most kmsg bytes, `High` survives the most damage on a cracked or
+/// glare-covered screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EcLevel {
+    L = 0,
+    M = 1,
+    Q = 2,
+    H = 3,
+}
+
+impl EcLevel {
+    // Map the C-side integer level, defaulting to Low for unknown values.
+    fn from_u8(level: u8) -> EcLevel {
+        match level {
+            1 => EcLevel::M,
+            2 => EcLevel::Q,
+            3 => EcLevel::H,
+            _ => EcLevel::L,
+        }
+    }
+}
+
+/// QRCode parameter for one (error correction level, version):
 /// - Error Correction polynomial
 /// - Number of blocks in group 1
 /// - Number of blocks in group 2
@@ -66,47 +108,181 @@ const P30: [u8; 30] = [
 /// (Block size in group 2 is one more than group 1)
 
 struct VersionParameter(&'static [u8], u8, u8, u8);
-const VPARAM: [VersionParameter; 40] = [
-    VersionParameter(&P7, 1, 0, 19),    // V1
-    VersionParameter(&P10, 1, 0, 34),   // V2
-    VersionParameter(&P15, 1, 0, 55),   // V3
-    VersionParameter(&P20, 1, 0, 80),   // V4
-    VersionParameter(&P26, 1, 0, 108),  // V5
-    VersionParameter(&P18, 2, 0, 68),   // V6
-    VersionParameter(&P20, 2, 0, 78),   // V7
-    VersionParameter(&P24, 2, 0, 97),   // V8
-    VersionParameter(&P30, 2, 0, 116),  // V9
-    VersionParameter(&P18, 2, 2, 68),   // V10
-    VersionParameter(&P20, 4, 0, 81),   // V11
-    VersionParameter(&P24, 2, 2, 92),   // V12
-    VersionParameter(&P26, 4, 0, 107),  // V13
-    VersionParameter(&P30, 3, 1, 115),  // V14
-    VersionParameter(&P22, 5, 1, 87),   // V15
-    VersionParameter(&P24, 5, 1, 98),   // V16
-    VersionParameter(&P28, 1, 5, 107),  // V17
-    VersionParameter(&P30, 5, 1, 120),  // V18
-    VersionParameter(&P28, 3, 4, 113),  // V19
-    VersionParameter(&P28, 3, 5, 107),  // V20
-    VersionParameter(&P28, 4, 4, 116),  // V21
-    VersionParameter(&P28, 2, 7, 111),  // V22
-    VersionParameter(&P30, 4, 5, 121),  // V23
-    VersionParameter(&P30, 6, 4, 117),  // V24
-    VersionParameter(&P26, 8, 4, 106),  // V25
-    VersionParameter(&P28, 10, 2, 114), // V26
-    VersionParameter(&P30, 8, 4, 122),  // V27
-    VersionParameter(&P30, 3, 10, 117), // V28
-    VersionParameter(&P30, 7, 7, 116),  // V29
-    VersionParameter(&P30, 5, 10, 115), // V30
-    VersionParameter(&P30, 13, 3, 115), // V31
-    VersionParameter(&P30, 17, 0, 115), // V32
-    VersionParameter(&P30, 17, 1, 115), // V33
-    VersionParameter(&P30, 13, 6, 115), // V34
-    VersionParameter(&P30, 12, 7, 121), // V35
-    VersionParameter(&P30, 6, 14, 121), // V36
-    VersionParameter(&P30, 17, 4, 122), // V37
-    VersionParameter(&P30, 4, 18, 122), // V38
-    VersionParameter(&P30, 20, 4, 117), // V39
-    VersionParameter(&P30, 19, 6, 118), // V40
+
+/// Per error-correction-level block layout, indexed `VPARAM[level][version - 1]`.
+const VPARAM: [[VersionParameter; 40]; 4] = [
+    // Low
+    [
+        VersionParameter(&P7, 1, 0, 19),    // V1
+        VersionParameter(&P10, 1, 0, 34),   // V2
+        VersionParameter(&P15, 1, 0, 55),   // V3
+        VersionParameter(&P20, 1, 0, 80),   // V4
+        VersionParameter(&P26, 1, 0, 108),  // V5
+        VersionParameter(&P18, 2, 0, 68),   // V6
+        VersionParameter(&P20, 2, 0, 78),   // V7
+        VersionParameter(&P24, 2, 0, 97),   // V8
+        VersionParameter(&P30, 2, 0, 116),  // V9
+        VersionParameter(&P18, 2, 2, 68),   // V10
+        VersionParameter(&P20, 4, 0, 81),   // V11
+        VersionParameter(&P24, 2, 2, 92),   // V12
+        VersionParameter(&P26, 4, 0, 107),  // V13
+        VersionParameter(&P30, 3, 1, 115),  // V14
+        VersionParameter(&P22, 5, 1, 87),   // V15
+        VersionParameter(&P24, 5, 1, 98),   // V16
+        VersionParameter(&P28, 1, 5, 107),  // V17
+        VersionParameter(&P30, 5, 1, 120),  // V18
+        VersionParameter(&P28, 3, 4, 113),  // V19
+        VersionParameter(&P28, 3, 5, 107),  // V20
+        VersionParameter(&P28, 4, 4, 116),  // V21
+        VersionParameter(&P28, 2, 7, 111),  // V22
+        VersionParameter(&P30, 4, 5, 121),  // V23
+        VersionParameter(&P30, 6, 4, 117),  // V24
+        VersionParameter(&P26, 8, 4, 106),  // V25
+        VersionParameter(&P28, 10, 2, 114), // V26
+        VersionParameter(&P30, 8, 4, 122),  // V27
+        VersionParameter(&P30, 3, 10, 117), // V28
+        VersionParameter(&P30, 7, 7, 116),  // V29
+        VersionParameter(&P30, 5, 10, 115), // V30
+        VersionParameter(&P30, 13, 3, 115), // V31
+        VersionParameter(&P30, 17, 0, 115), // V32
+        VersionParameter(&P30, 17, 1, 115), // V33
+        VersionParameter(&P30, 13, 6, 115), // V34
+        VersionParameter(&P30, 12, 7, 121), // V35
+        VersionParameter(&P30, 6, 14, 121), // V36
+        VersionParameter(&P30, 17, 4, 122), // V37
+        VersionParameter(&P30, 4, 18, 122), // V38
+        VersionParameter(&P30, 20, 4, 117), // V39
+        VersionParameter(&P30, 19, 6, 118), // V40
+    ],
+    // Medium
+    [
+        VersionParameter(&P10, 1, 0, 16),   // V1
+        VersionParameter(&P16, 1, 0, 28),   // V2
+        VersionParameter(&P26, 1, 0, 44),   // V3
+        VersionParameter(&P18, 2, 0, 32),   // V4
+        VersionParameter(&P24, 2, 0, 43),   // V5
+        VersionParameter(&P16, 4, 0, 27),   // V6
+        VersionParameter(&P18, 4, 0, 31),   // V7
+        VersionParameter(&P22, 2, 2, 38),   // V8
+        VersionParameter(&P22, 3, 2, 36),   // V9
+        VersionParameter(&P26, 4, 1, 43),   // V10
+        VersionParameter(&P30, 1, 4, 50),   // V11
+        VersionParameter(&P22, 6, 2, 36),   // V12
+        VersionParameter(&P22, 8, 1, 37),   // V13
+        VersionParameter(&P24, 4, 5, 40),   // V14
+        VersionParameter(&P24, 5, 5, 41),   // V15
+        VersionParameter(&P28, 7, 3, 45),   // V16
+        VersionParameter(&P28, 10, 1, 46),  // V17
+        VersionParameter(&P26, 9, 4, 43),   // V18
+        VersionParameter(&P26, 3, 11, 44),  // V19
+        VersionParameter(&P26, 3, 13, 41),  // V20
+        VersionParameter(&P26, 17, 0, 42),  // V21
+        VersionParameter(&P28, 17, 0, 46),  // V22
+        VersionParameter(&P28, 4, 14, 47),  // V23
+        VersionParameter(&P28, 6, 14, 45),  // V24
+        VersionParameter(&P28, 8, 13, 47),  // V25
+        VersionParameter(&P28, 19, 4, 46),  // V26
+        VersionParameter(&P28, 22, 3, 45),  // V27
+        VersionParameter(&P28, 3, 23, 45),  // V28
+        VersionParameter(&P28, 21, 7, 45),  // V29
+        VersionParameter(&P28, 19, 10, 47), // V30
+        VersionParameter(&P28, 2, 29, 46),  // V31
+        VersionParameter(&P28, 10, 23, 46), // V32
+        VersionParameter(&P28, 14, 21, 46), // V33
+        VersionParameter(&P28, 14, 23, 46), // V34
+        VersionParameter(&P28, 12, 26, 47), // V35
+        VersionParameter(&P28, 6, 34, 47),  // V36
+        VersionParameter(&P28, 29, 14, 46), // V37
+        VersionParameter(&P28, 13, 32, 46), // V38
+        VersionParameter(&P28, 40, 7, 47),  // V39
+        VersionParameter(&P28, 18, 31, 47), // V40
+    ],
+    // Quartile
+    [
+        VersionParameter(&P13, 1, 0, 13),   // V1
+        VersionParameter(&P22, 1, 0, 22),   // V2
+        VersionParameter(&P18, 2, 0, 17),   // V3
+        VersionParameter(&P26, 2, 0, 24),   // V4
+        VersionParameter(&P18, 2, 2, 15),   // V5
+        VersionParameter(&P24, 4, 0, 19),   // V6
+        VersionParameter(&P18, 2, 4, 14),   // V7
+        VersionParameter(&P22, 4, 2, 18),   // V8
+        VersionParameter(&P20, 4, 4, 16),   // V9
+        VersionParameter(&P24, 6, 2, 19),   // V10
+        VersionParameter(&P28, 4, 4, 22),   // V11
+        VersionParameter(&P26, 4, 6, 20),   // V12
+        VersionParameter(&P24, 8, 4, 20),   // V13
+        VersionParameter(&P20, 11, 5, 16),  // V14
+        VersionParameter(&P30, 5, 7, 24),   // V15
+        VersionParameter(&P24, 15, 2, 19),  // V16
+        VersionParameter(&P28, 1, 15, 22),  // V17
+        VersionParameter(&P28, 17, 1, 22),  // V18
+        VersionParameter(&P26, 17, 4, 21),  // V19
+        VersionParameter(&P30, 15, 5, 24),  // V20
+        VersionParameter(&P28, 17, 6, 22),  // V21
+        VersionParameter(&P30, 7, 16, 24),  // V22
+        VersionParameter(&P30, 11, 14, 24), // V23
+        VersionParameter(&P30, 11, 16, 24), // V24
+        VersionParameter(&P30, 7, 22, 24),  // V25
+        VersionParameter(&P28, 28, 6, 22),  // V26
+        VersionParameter(&P30, 8, 26, 23),  // V27
+        VersionParameter(&P30, 4, 31, 24),  // V28
+        VersionParameter(&P30, 1, 37, 23),  // V29
+        VersionParameter(&P30, 15, 25, 24), // V30
+        VersionParameter(&P30, 42, 1, 24),  // V31
+        VersionParameter(&P30, 10, 35, 24), // V32
+        VersionParameter(&P30, 29, 19, 24), // V33
+        VersionParameter(&P30, 44, 7, 24),  // V34
+        VersionParameter(&P30, 39, 14, 24), // V35
+        VersionParameter(&P30, 46, 10, 24), // V36
+        VersionParameter(&P30, 49, 10, 24), // V37
+        VersionParameter(&P30, 48, 14, 24), // V38
+        VersionParameter(&P30, 43, 22, 24), // V39
+        VersionParameter(&P30, 34, 34, 24), // V40
+    ],
+    // High
+    [
+        VersionParameter(&P17, 1, 0, 9),    // V1
+        VersionParameter(&P28, 1, 0, 16),   // V2
+        VersionParameter(&P22, 2, 0, 13),   // V3
+        VersionParameter(&P16, 4, 0, 9),    // V4
+        VersionParameter(&P22, 2, 2, 11),   // V5
+        VersionParameter(&P28, 4, 0, 15),   // V6
+        VersionParameter(&P26, 4, 1, 13),   // V7
+        VersionParameter(&P26, 4, 2, 14),   // V8
+        VersionParameter(&P24, 4, 4, 12),   // V9
+        VersionParameter(&P28, 6, 2, 15),   // V10
+        VersionParameter(&P24, 3, 8, 12),   // V11
+        VersionParameter(&P28, 7, 4, 14),   // V12
+        VersionParameter(&P22, 12, 4, 11),  // V13
+        VersionParameter(&P24, 11, 5, 12),  // V14
+        VersionParameter(&P24, 11, 7, 12),  // V15
+        VersionParameter(&P30, 3, 13, 15),  // V16
+        VersionParameter(&P28, 2, 17, 14),  // V17
+        VersionParameter(&P28, 2, 19, 14),  // V18
+        VersionParameter(&P26, 9, 16, 13),  // V19
+        VersionParameter(&P28, 15, 10, 15), // V20
+        VersionParameter(&P30, 19, 6, 16),  // V21
+        VersionParameter(&P24, 34, 0, 13),  // V22
+        VersionParameter(&P30, 16, 14, 15), // V23
+        VersionParameter(&P30, 30, 2, 16),  // V24
+        VersionParameter(&P30, 22, 13, 15), // V25
+        VersionParameter(&P30, 33, 4, 16),  // V26
+        VersionParameter(&P30, 12, 28, 15), // V27
+        VersionParameter(&P30, 11, 31, 15), // V28
+        VersionParameter(&P30, 19, 26, 15), // V29
+        VersionParameter(&P30, 23, 25, 15), // V30
+        VersionParameter(&P30, 23, 28, 15), // V31
+        VersionParameter(&P30, 19, 35, 15), // V32
+        VersionParameter(&P30, 11, 46, 15), // V33
+        VersionParameter(&P30, 59, 1, 16),  // V34
+        VersionParameter(&P30, 22, 41, 15), // V35
+        VersionParameter(&P30, 2, 64, 15),  // V36
+        VersionParameter(&P30, 24, 46, 15), // V37
+        VersionParameter(&P30, 42, 32, 15), // V38
+        VersionParameter(&P30, 10, 67, 15), // V39
+        VersionParameter(&P30, 20, 61, 15), // V40
+    ],
 ];
 
 const MAX_EC_SIZE: usize = 30;
@@ -194,16 +370,23 @@ const VERSION_INFORMATION: [u32; 34] = [
     0b10_1000_1100_0110_1001,
 ];
 
-/// Format info for Low EC
-const FORMAT_INFOS_QR_L: [u16; 8] = [
-    0x77c4, 0x72f3, 0x7daa, 0x789d, 0x662f, 0x6318, 0x6c41, 0x6976,
+/// 15-bit format info, indexed `FORMAT_INFOS[level][mask]`.
+const FORMAT_INFOS: [[u16; 8]; 4] = [
+    // Low
+    [0x77c4, 0x72f3, 0x7daa, 0x789d, 0x662f, 0x6318, 0x6c41, 0x6976],
+    // Medium
+    [0x5412, 0x5125, 0x5e7c, 0x5b4b, 0x45f9, 0x40ce, 0x4f97, 0x4aa0],
+    // Quartile
+    [0x355f, 0x3068, 0x3f31, 0x3a06, 0x24b4, 0x2183, 0x2eda, 0x2bed],
+    // High
+    [0x1689, 0x13be, 0x1ce7, 0x19d0, 0x0762, 0x0255, 0x0d0c, 0x083b],
 ];
 
 impl Version {
     // Return the smallest QR Version than can hold these segments
-    fn from_segments(segments: &[&Segment<'_>]) -> Option<Version> {
+    fn from_segments(segments: &[&Segment<'_>], ec: EcLevel) -> Option<Version> {
         for v in (1..=40).map(|k| Version(k)) {
-            if v.max_data() * 8 >= segments.iter().map(|s| s.total_size_bits(v)).sum() {
+            if v.max_data(ec) * 8 >= segments.iter().map(|s| s.total_size_bits(v)).sum() {
                 return Some(v);
             }
         }
@@ -214,32 +397,33 @@ impl Version {
         (self.0 as u8) * 4 + 17
     }
 
-    fn max_data(&self) -> usize {
-        self.g1_blk_size() * self.g1_blocks() + (self.g1_blk_size() + 1) * self.g2_blocks()
+    fn max_data(&self, ec: EcLevel) -> usize {
+        self.g1_blk_size(ec) * self.g1_blocks(ec)
+            + (self.g1_blk_size(ec) + 1) * self.g2_blocks(ec)
     }
 
-    fn ec_size(&self) -> usize {
-        VPARAM[self.0 - 1].0.len()
+    fn ec_size(&self, ec: EcLevel) -> usize {
+        VPARAM[ec as usize][self.0 - 1].0.len()
     }
 
-    fn g1_blocks(&self) -> usize {
-        VPARAM[self.0 - 1].1 as usize
+    fn g1_blocks(&self, ec: EcLevel) -> usize {
+        VPARAM[ec as usize][self.0 - 1].1 as usize
     }
 
-    fn g2_blocks(&self) -> usize {
-        VPARAM[self.0 - 1].2 as usize
+    fn g2_blocks(&self, ec: EcLevel) -> usize {
+        VPARAM[ec as usize][self.0 - 1].2 as usize
     }
 
-    fn g1_blk_size(&self) -> usize {
-        VPARAM[self.0 - 1].3 as usize
+    fn g1_blk_size(&self, ec: EcLevel) -> usize {
+        VPARAM[ec as usize][self.0 - 1].3 as usize
     }
 
     fn alignment_pattern(&self) -> &'static [u8] {
         &ALIGNMENT_PATTERNS[self.0 - 1]
     }
 
-    fn poly(&self) -> &'static [u8] {
-        VPARAM[self.0 - 1].0
+    fn poly(&self, ec: EcLevel) -> &'static [u8] {
+        VPARAM[ec as usize][self.0 - 1].0
     }
 
     fn version_info(&self) -> u32 {
@@ -288,7 +472,14 @@ const LOG_TABLE: [u8; 256] = [
 // 4 bits segment header
 const MODE_STOP: u16 = 0;
 const MODE_NUMERIC: u16 = 1;
+const MODE_ALPHANUMERIC: u16 = 2;
+const MODE_STRUCTURED: u16 = 3;
 const MODE_BINARY: u16 = 4;
+
+// Structured-append header: mode (4) + symbol index (4) + count-1 (4) + parity (8)
+const STRUCTURED_APPEND_BITS: usize = 20;
+// Maximum number of chained symbols in a structured-append sequence.
+const MAX_STRUCTURED_SYMBOLS: usize = 16;
 // padding bytes
 const PADDING: [u8; 2] = [236, 17];
 
@@ -322,16 +513,94 @@ fn get_next_13b(data: &[u8], offset: usize) -> Option<(u16, usize)> {
 const NUM_CHARS_BITS: [usize; 4] = [0, 4, 7, 10];
 const POW10: [u16; 4] = [1, 10, 100, 1000];
 
+/// Value of a byte in the QR alphanumeric charset
+/// (`0-9 A-Z space $ % * + - . / :`), or None if it is not part of it.
+fn alnum_value(b: u8) -> Option<u16> {
+    match b {
+        b'0'..=b'9' => Some((b - b'0') as u16),
+        b'A'..=b'Z' => Some((b - b'A') as u16 + 10),
+        b' ' => Some(36),
+        b'$' => Some(37),
+        b'%' => Some(38),
+        b'*' => Some(39),
+        b'+' => Some(40),
+        b'-' => Some(41),
+        b'.' => Some(42),
+        b'/' => Some(43),
+        b':' => Some(44),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy)]
 enum Segment<'a> {
+    /// Raw bytes, repacked 13 bits at a time into 4 decimal digits and encoded
+    /// in numeric mode (see `character_count`).
     Numeric(&'a [u8]),
+    /// Bytes of the `0-9 A-Z space $ % * + - . / :` charset, packed 11 bits per
+    /// pair and 6 bits for a trailing single character (~5.5 bits/char, against
+    /// the 8 bits/char of a binary segment).
+    Alphanumeric(&'a [u8]),
+    /// Raw bytes, 8 bits each.
     Binary(&'a [u8]),
 }
 
+/// Maximum number of segments the optimizer emits.
+const MAX_SEGMENTS: usize = 8;
+/// Maximum number of single-mode runs handled before falling back to a single
+/// binary segment. Kernel panic text is full of lowercase hex addresses,
+/// which alternate runs against adjacent digits (the alphanumeric charset
+/// only covers uppercase), so 32 ran out quickly and fell back to binary on
+/// most such logs; 64 gives headroom for that without following the original
+/// fallback's own math all the way to MAX_SEGMENTS, which bounds the final
+/// segment count, not the number of runs `optimize` walks to get there, and
+/// so can't substitute for this constant. `optimize`'s stack frame (`bounds`,
+/// `cost`, `back`: `[usize; MAX_RUNS + 1]` each, plus `segs`:
+/// `[(usize, usize, Mode); MAX_RUNS]`) grows from ~1.6KB at 32 to ~3.1KB at
+/// 64 here, versus ~6.3KB it would be at 128.
+const MAX_RUNS: usize = 64;
+
+/// A per-character encoding mode considered by the segment optimizer.
+///
+/// `Segment::Numeric` is deliberately not a candidate here. It isn't QR
+/// numeric mode in the usual sense of literal digit characters: it repacks
+/// 13 raw input bits at a time into up to 4 "digits" (0-9999) and encodes
+/// those through the standard numeric packing, which only round-trips back
+/// to the original bytes through that specific repacking scheme. Running it
+/// over arbitrary optimizer text would make a standards-compliant scanner
+/// decode garbage digits instead of the panic text, so this is restricted to
+/// the URL-safe compressed-payload channel that already knows how to reverse
+/// it, never to general-purpose text segmentation.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Alphanumeric,
+    Binary,
+}
+
+impl Mode {
+    /// Densest mode able to encode a single byte.
+    fn classify(b: u8) -> Mode {
+        if alnum_value(b).is_some() {
+            Mode::Alphanumeric
+        } else {
+            Mode::Binary
+        }
+    }
+
+    fn segment(self, data: &[u8]) -> Segment<'_> {
+        match self {
+            Mode::Alphanumeric => Segment::Alphanumeric(data),
+            Mode::Binary => Segment::Binary(data),
+        }
+    }
+}
+
 impl Segment<'_> {
     fn get_header(&self) -> (u16, usize) {
         match self {
             Segment::Binary(_) => (MODE_BINARY, 4),
             Segment::Numeric(_) => (MODE_NUMERIC, 4),
+            Segment::Alphanumeric(_) => (MODE_ALPHANUMERIC, 4),
         }
     }
 
@@ -348,6 +617,11 @@ impl Segment<'_> {
                 10..=26 => 12,
                 _ => 14,
             },
+            Segment::Alphanumeric(_) => match v {
+                1..=9 => 9,
+                10..=26 => 11,
+                _ => 13,
+            },
         }
     }
 
@@ -355,6 +629,7 @@ impl Segment<'_> {
     fn character_count(&self) -> usize {
         match self {
             Segment::Binary(data) => data.len(),
+            Segment::Alphanumeric(data) => data.len(),
             Segment::Numeric(data) => {
                 let data_bits = data.len() * 8;
                 let last_chars = match data_bits % 13 {
@@ -377,6 +652,7 @@ impl Segment<'_> {
     fn total_size_bits(&self, version: Version) -> usize {
         let data_size = match self {
             Segment::Binary(data) => data.len() * 8,
+            Segment::Alphanumeric(data) => 11 * (data.len() / 2) + 6 * (data.len() % 2),
             Segment::Numeric(_) => {
                 let digits = self.character_count();
                 10 * (digits / 3) + NUM_CHARS_BITS[digits % 3]
@@ -395,6 +671,121 @@ impl Segment<'_> {
     }
 }
 
+impl<'a> Segment<'a> {
+    /// Split `data` into the cheapest sequence of segments for `version`.
+    ///
+    /// The segmentation is a shortest path over byte positions: staying in a
+    /// mode pays that mode's per-character cost, while switching pays the 4-bit
+    /// mode header plus the version-dependent count field (`length_bits_count`).
+    /// The input is first reduced to maximal single-mode runs, then a DP over
+    /// the run boundaries picks, for each span, the cheapest legal mode.
+    ///
+    /// The chosen segments are written to `out` (left to right) and the count
+    /// is returned. Everything is kept on the stack; a pathologically
+    /// fragmented input falls back to a single binary segment.
+    fn optimize(data: &'a [u8], version: Version, out: &mut [Segment<'a>]) -> usize {
+        if data.is_empty() || out.is_empty() {
+            return 0;
+        }
+
+        // Split the input into maximal runs of a single mode.
+        let mut bounds = [0usize; MAX_RUNS + 1];
+        let mut modes = [Mode::Binary; MAX_RUNS];
+        let mut runs = 0;
+        let mut i = 0;
+        while i < data.len() {
+            if runs >= MAX_RUNS {
+                out[0] = Segment::Binary(data);
+                return 1;
+            }
+            let m = Mode::classify(data[i]);
+            bounds[runs] = i;
+            while i < data.len() && Mode::classify(data[i]) == m {
+                i += 1;
+            }
+            modes[runs] = m;
+            runs += 1;
+        }
+        bounds[runs] = data.len();
+
+        // cost[i] is the cheapest encoding of runs[0..i], back[i] the first run
+        // of the last segment on that path.
+        let mut cost = [usize::MAX; MAX_RUNS + 1];
+        let mut back = [0usize; MAX_RUNS + 1];
+        cost[0] = 0;
+        for i in 1..=runs {
+            for j in 0..i {
+                if cost[j] == usize::MAX {
+                    continue;
+                }
+                let c = cost[j] + Self::span_cost(data, &bounds, &modes, j, i, version);
+                if c < cost[i] {
+                    cost[i] = c;
+                    back[i] = j;
+                }
+            }
+        }
+
+        // Backtrack to recover the segments, then emit them in order.
+        let mut segs = [(0usize, 0usize, Mode::Binary); MAX_RUNS];
+        let mut n = 0;
+        let mut i = runs;
+        while i > 0 {
+            let j = back[i];
+            segs[n] = (bounds[j], bounds[i], Self::span_mode(data, &bounds, &modes, j, i, version));
+            n += 1;
+            i = j;
+        }
+
+        if n > out.len() {
+            out[0] = Segment::Binary(data);
+            return 1;
+        }
+        for k in 0..n {
+            let (start, end, mode) = segs[n - 1 - k];
+            out[k] = mode.segment(&data[start..end]);
+        }
+        n
+    }
+
+    /// Cheapest cost, in bits, of a single segment spanning runs `j..i`.
+    fn span_cost(
+        data: &[u8],
+        bounds: &[usize],
+        modes: &[Mode],
+        j: usize,
+        i: usize,
+        version: Version,
+    ) -> usize {
+        let span = &data[bounds[j]..bounds[i]];
+        let mut best = Segment::Binary(span).total_size_bits(version);
+        if modes[j..i].iter().all(|&m| m == Mode::Alphanumeric) {
+            best = cmp::min(best, Segment::Alphanumeric(span).total_size_bits(version));
+        }
+        best
+    }
+
+    /// Mode achieving `span_cost` for runs `j..i`.
+    fn span_mode(
+        data: &[u8],
+        bounds: &[usize],
+        modes: &[Mode],
+        j: usize,
+        i: usize,
+        version: Version,
+    ) -> Mode {
+        let span = &data[bounds[j]..bounds[i]];
+        if modes[j..i].iter().all(|&m| m == Mode::Alphanumeric)
+            && Segment::Alphanumeric(span).total_size_bits(version)
+                <= Segment::Binary(span).total_size_bits(version)
+        {
+            Mode::Alphanumeric
+        } else {
+            Mode::Binary
+        }
+    }
+}
+
 struct SegmentIterator<'a> {
     segment: &'a Segment<'a>,
     offset: usize,
@@ -416,6 +807,20 @@ impl Iterator for SegmentIterator<'_> {
                     None
                 }
             }
+            Segment::Alphanumeric(data) => {
+                if self.offset + 1 < data.len() {
+                    let c1 = alnum_value(data[self.offset]).unwrap_or(0);
+                    let c2 = alnum_value(data[self.offset + 1]).unwrap_or(0);
+                    self.offset += 2;
+                    Some((c1 * 45 + c2, 11))
+                } else if self.offset < data.len() {
+                    let c = alnum_value(data[self.offset]).unwrap_or(0);
+                    self.offset += 1;
+                    Some((c, 6))
+                } else {
+                    None
+                }
+            }
             Segment::Numeric(data) => {
                 if self.carry_len == 3 {
                     let out = (self.carry, NUM_CHARS_BITS[self.carry_len]);
@@ -468,6 +873,7 @@ struct EncodedMsg<'a> {
     poly: &'static [u8],
     current: usize,
     version: Version,
+    ec: EcLevel,
 }
 
 /// EncodedMsg will hold the data to be put in the QR-Code, with correct segment
@@ -475,13 +881,13 @@ struct EncodedMsg<'a> {
 /// It also implements an iterator to retrieve the data interleaved to draw the
 /// QR-code image.
 impl EncodedMsg<'_> {
-    fn init<'a>(version: Version, data: &'a mut [u8]) -> EncodedMsg<'a> {
-        let ec_size = version.ec_size();
-        let g1_blocks = version.g1_blocks();
-        let g2_blocks = version.g2_blocks();
-        let g1_blk_size = version.g1_blk_size();
+    fn init<'a>(version: Version, ec: EcLevel, data: &'a mut [u8]) -> EncodedMsg<'a> {
+        let ec_size = version.ec_size(ec);
+        let g1_blocks = version.g1_blocks(ec);
+        let g2_blocks = version.g2_blocks(ec);
+        let g1_blk_size = version.g1_blk_size(ec);
         let g2_blk_size = g1_blk_size + 1;
-        let poly = version.poly();
+        let poly = version.poly(ec);
 
         // clear the output
         data.fill(0);
@@ -497,6 +903,7 @@ impl EncodedMsg<'_> {
             poly,
             current: 0,
             version,
+            ec,
         }
     }
 
@@ -542,7 +949,7 @@ impl EncodedMsg<'_> {
         self.push((MODE_STOP, 4));
 
         let pad_offset = (self.offset + 7) / 8;
-        for i in pad_offset..self.version.max_data() {
+        for i in pad_offset..self.version.max_data(self.ec) {
             self.data[i] = PADDING[(i & 1) ^ (pad_offset & 1)];
         }
     }
@@ -588,6 +995,87 @@ impl EncodedMsg<'_> {
         self.finish();
         self.compute_error_code();
     }
+
+    // Structured-append header, prefixing the symbol at `index` of a `count`
+    // symbol sequence whose combined data bytes xor to `parity`.
+    fn add_structured_append(&mut self, index: u8, count: u8, parity: u8) {
+        self.push((MODE_STRUCTURED, 4));
+        self.push((index as u16, 4));
+        self.push(((count - 1) as u16, 4));
+        self.push((parity as u16, 8));
+    }
+
+    // Encode one symbol of a structured-append sequence.
+    fn encode_structured(&mut self, index: u8, count: u8, parity: u8, segment: &Segment<'_>) {
+        self.add_structured_append(index, count, parity);
+        self.add_segment(segment);
+        self.finish();
+        self.compute_error_code();
+    }
+}
+
+/// Map the `current`-th interleaved codeword to its offset in the linear
+/// data+EC buffer, for the given block layout. Shared by the draw iterator and
+/// the self-verify read-back.
+fn interleaved_offset(
+    current: usize,
+    g1_blocks: usize,
+    g2_blocks: usize,
+    g1_blk_size: usize,
+    g2_blk_size: usize,
+    ec_size: usize,
+) -> usize {
+    let blocks = g1_blocks + g2_blocks;
+    let g1_end = g1_blocks * g1_blk_size;
+    let g2_end = g1_end + g2_blocks * g2_blk_size;
+
+    if current < g1_blk_size * blocks {
+        // group1 and group2 interleaved
+        let blk = current % blocks;
+        let blk_off = current / blocks;
+        if blk < g1_blocks {
+            blk * g1_blk_size + blk_off
+        } else {
+            g1_end + g2_blk_size * (blk - g1_blocks) + blk_off
+        }
+    } else if current < g2_end {
+        // last byte of group2 blocks
+        let blk2 = current - blocks * g1_blk_size;
+        g1_blk_size * g1_blocks + blk2 * g2_blk_size + g2_blk_size - 1
+    } else {
+        // EC blocks
+        let ec_offset = current - g2_end;
+        let blk = ec_offset % blocks;
+        let blk_off = ec_offset / blocks;
+
+        g2_end + blk * ec_size + blk_off
+    }
+}
+
+/// Evaluate the Reed-Solomon syndromes of one block (its `size` data bytes at
+/// `offset` followed by its `ec_size` EC bytes at `ec_offset`) and return true
+/// if they all vanish, i.e. the codeword is error free.
+#[cfg(debug_assertions)]
+fn syndromes_zero(data: &[u8], offset: usize, size: usize, ec_offset: usize, ec_size: usize) -> bool {
+    let n = size + ec_size;
+    for j in 0..ec_size {
+        let mut syndrome: u8 = 0;
+        for i in 0..n {
+            let coeff = if i < size {
+                data[offset + i]
+            } else {
+                data[ec_offset + i - size]
+            };
+            if coeff != 0 {
+                let exp = (usize::from(LOG_TABLE[coeff as usize]) + j * (n - 1 - i)) % 255;
+                syndrome ^= EXP_TABLE[exp];
+            }
+        }
+        if syndrome != 0 {
+            return false;
+        }
+    }
+    true
 }
 
 impl Iterator for EncodedMsg<'_> {
@@ -597,35 +1085,21 @@ impl Iterator for EncodedMsg<'_> {
     // second block of group1, ...
     fn next(&mut self) -> Option<Self::Item> {
         let blocks = self.g1_blocks + self.g2_blocks;
-        let g1_end = self.g1_blocks * self.g1_blk_size;
-        let g2_end = g1_end + self.g2_blocks * self.g2_blk_size;
+        let g2_end = self.g1_blocks * self.g1_blk_size + self.g2_blocks * self.g2_blk_size;
         let ec_end = g2_end + self.ec_size * blocks;
 
         if self.current >= ec_end {
             return None;
         }
 
-        let offset = if self.current < self.g1_blk_size * blocks {
-            // group1 and group2 interleaved
-            let blk = self.current % blocks;
-            let blk_off = self.current / blocks;
-            if blk < self.g1_blocks {
-                blk * self.g1_blk_size + blk_off
-            } else {
-                g1_end + self.g2_blk_size * (blk - self.g1_blocks) + blk_off
-            }
-        } else if self.current < g2_end {
-            // last byte of group2 blocks
-            let blk2 = self.current - blocks * self.g1_blk_size;
-            self.g1_blk_size * self.g1_blocks + blk2 * self.g2_blk_size + self.g2_blk_size - 1
-        } else {
-            // EC blocks
-            let ec_offset = self.current - g2_end;
-            let blk = ec_offset % blocks;
-            let blk_off = ec_offset / blocks;
-
-            g2_end + blk * self.ec_size + blk_off
-        };
+        let offset = interleaved_offset(
+            self.current,
+            self.g1_blocks,
+            self.g2_blocks,
+            self.g1_blk_size,
+            self.g2_blk_size,
+            self.ec_size,
+        );
         self.current += 1;
         Some(self.data[offset])
     }
@@ -640,12 +1114,14 @@ pub struct QrImage<'a> {
     width: u8,
     stride: u8,
     version: Version,
+    ec: EcLevel,
+    mask: u8,
     x: u8,
     y: u8,
 }
 
 impl QrImage<'_> {
-    fn init<'a>(version: Version, qrdata: &'a mut [u8]) -> QrImage<'a> {
+    fn init<'a>(version: Version, ec: EcLevel, qrdata: &'a mut [u8]) -> QrImage<'a> {
         let width = version.width();
         let stride = (width + 7) / 8;
         let data = qrdata;
@@ -655,6 +1131,8 @@ impl QrImage<'_> {
             width,
             stride,
             version,
+            ec,
+            mask: 0,
             x: width - 2,
             y: width,
         }
@@ -672,6 +1150,18 @@ impl QrImage<'_> {
         self.data[off] = v;
     }
 
+    // clear pixel to dark color
+    fn clear_pixel(&mut self, x: u8, y: u8) {
+        let off = y as usize * self.stride as usize + x as usize / 8;
+        self.data[off] &= !(1 << 7 - (x % 8));
+    }
+
+    // return true if the pixel is light
+    fn get(&self, x: u8, y: u8) -> bool {
+        let off = y as usize * self.stride as usize + x as usize / 8;
+        self.data[off] & (1 << 7 - (x % 8)) != 0
+    }
+
     // Invert a pixel color
     fn xor(&mut self, x: u8, y: u8) {
         let off = y as usize * self.stride as usize + x as usize / 8;
@@ -752,8 +1242,8 @@ impl QrImage<'_> {
     }
 
     // mask info : 15 bits around the finders, written twice for redundancy
-    fn draw_maskinfo(&mut self) {
-        let info: u16 = FORMAT_INFOS_QR_L[0];
+    fn draw_maskinfo(&mut self, mask: u8) {
+        let info: u16 = FORMAT_INFOS[self.ec as usize][mask as usize];
         let mut skip = 0;
 
         for k in 0..7 {
@@ -871,17 +1361,136 @@ impl QrImage<'_> {
         }
     }
 
-    // Apply checkboard mask to all non-reserved modules
-    fn apply_mask(&mut self) {
+    // Return true when data mask `mask` inverts module (x, y).
+    fn mask_bit(mask: u8, x: u8, y: u8) -> bool {
+        let r = y as u32;
+        let c = x as u32;
+        match mask {
+            0 => (r + c) % 2 == 0,
+            1 => r % 2 == 0,
+            2 => c % 3 == 0,
+            3 => (r + c) % 3 == 0,
+            4 => (r / 2 + c / 3) % 2 == 0,
+            5 => (r * c) % 2 + (r * c) % 3 == 0,
+            6 => ((r * c) % 2 + (r * c) % 3) % 2 == 0,
+            _ => ((r + c) % 2 + (r * c) % 3) % 2 == 0,
+        }
+    }
+
+    // Apply (or, since xor is its own inverse, revert) `mask` on all
+    // non-reserved modules.
+    fn apply_mask(&mut self, mask: u8) {
         for x in 0..self.width {
             for y in 0..self.width {
-                if (x ^ y) % 2 == 0 && !self.is_reserved(x, y) {
+                if Self::mask_bit(mask, x, y) && !self.is_reserved(x, y) {
                     self.xor(x, y);
                 }
             }
         }
     }
 
+    // Reset the mask-info modules to dark, to try another mask.
+    fn clear_maskinfo(&mut self) {
+        for x in 0..self.width {
+            for y in 0..self.width {
+                if self.is_maskinfo(x, y) {
+                    self.clear_pixel(x, y);
+                }
+            }
+        }
+    }
+
+    // Dark module at (x, y) along `line`, scanning horizontally or vertically.
+    fn is_dark(&self, horizontal: bool, line: u8, pos: u8) -> bool {
+        if horizontal {
+            !self.get(pos, line)
+        } else {
+            !self.get(line, pos)
+        }
+    }
+
+    // Match the 1:1:3:1:1 finder-like sequence (dark-light-dark-dark-dark-
+    // light-dark) bounded by 4 light modules, in either orientation, starting
+    // at `start` along `line`.
+    fn finder_like(&self, horizontal: bool, line: u8, start: u8) -> bool {
+        const BEFORE: [bool; 11] = [
+            true, false, true, true, true, false, true, false, false, false, false,
+        ];
+        const AFTER: [bool; 11] = [
+            false, false, false, false, true, false, true, true, true, false, true,
+        ];
+        let mut before = true;
+        let mut after = true;
+        for i in 0..11u8 {
+            let dark = self.is_dark(horizontal, line, start + i);
+            before &= dark == BEFORE[i as usize];
+            after &= dark == AFTER[i as usize];
+        }
+        before || after
+    }
+
+    // ISO 18004 penalty score of the current module matrix.
+    fn penalty(&self) -> u32 {
+        // Penalty weights from ISO 18004 (N1..N4).
+        const N1: u32 = 3;
+        const N2: u32 = 3;
+        const N3: u32 = 40;
+        const N4: u32 = 10;
+
+        let w = self.width;
+        let mut score: u32 = 0;
+        let mut dark: u32 = 0;
+
+        // Rule 1 (runs of 5+) and rule 3 (finder-like pattern), in rows then
+        // columns. Dark modules are counted once, during the row scan.
+        for horizontal in [true, false] {
+            for line in 0..w {
+                let mut run_color = false;
+                let mut run_len: u32 = 0;
+                for pos in 0..w {
+                    let d = self.is_dark(horizontal, line, pos);
+                    if horizontal && d {
+                        dark += 1;
+                    }
+                    if pos == 0 || d == run_color {
+                        run_len += 1;
+                    } else {
+                        if run_len >= 5 {
+                            score += N1 + (run_len - 5);
+                        }
+                        run_len = 1;
+                    }
+                    run_color = d;
+                    if pos >= 10 && self.finder_like(horizontal, line, pos - 10) {
+                        score += N3;
+                    }
+                }
+                if run_len >= 5 {
+                    score += N1 + (run_len - 5);
+                }
+            }
+        }
+
+        // Rule 2 : every 2x2 block of uniform color.
+        for y in 0..w - 1 {
+            for x in 0..w - 1 {
+                let c = self.get(x, y);
+                if self.get(x + 1, y) == c && self.get(x, y + 1) == c && self.get(x + 1, y + 1) == c
+                {
+                    score += N2;
+                }
+            }
+        }
+
+        // Rule 4 : deviation of the dark-module ratio from 50%.
+        let total = w as u32 * w as u32;
+        let percent = dark * 100 / total;
+        let dev = if percent >= 50 { percent - 50 } else { 50 - percent };
+        score += dev / 5 * N4;
+
+        score
+    }
+
     // draw the qrcode with the provided data iterator
     fn draw_all(&mut self, data: impl Iterator<Item = u8>) -> u8 {
         // first clear the table, as it has already some data.
@@ -892,38 +1501,218 @@ impl QrImage<'_> {
         self.draw_version_info();
         self.draw_data(data);
         self.draw_remaining();
-        self.draw_maskinfo();
-        self.apply_mask();
+
+        // Try all 8 masks and keep the one with the lowest penalty.
+        let mut best_mask: u8 = 0;
+        let mut best_score = u32::MAX;
+        for mask in 0..8u8 {
+            self.apply_mask(mask);
+            self.draw_maskinfo(mask);
+            let score = self.penalty();
+            if score < best_score {
+                best_score = score;
+                best_mask = mask;
+            }
+            self.clear_maskinfo();
+            self.apply_mask(mask);
+        }
+        self.apply_mask(best_mask);
+        self.draw_maskinfo(best_mask);
+        self.mask = best_mask;
         self.width
     }
+
+    // Read back the codeword byte at the current cursor, undoing the mask.
+    // Drawing made a module light for a 0 data bit, so a light (unmasked)
+    // module reads back as 0.
+    #[cfg(debug_assertions)]
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = 0;
+        for _ in 0..8 {
+            self.next();
+            while self.is_reserved(self.x, self.y) {
+                self.next();
+            }
+            let mut light = self.get(self.x, self.y);
+            if Self::mask_bit(self.mask, self.x, self.y) {
+                light = !light;
+            }
+            byte = (byte << 1) | u8::from(!light);
+        }
+        byte
+    }
+
+    /// Self-verify the rendered code against the encoded codewords in `tmp`.
+    ///
+    /// Reads the framebuffer back in the same order it was drawn, undoing the
+    /// data mask, and checks that each codeword matches `tmp` through the
+    /// shared de-interleave mapping. Then, for every block, it evaluates the
+    /// Reed-Solomon syndromes over GF(256) and confirms they vanish. Entirely
+    /// stack based, gated to debug builds.
+    #[cfg(debug_assertions)]
+    fn verify(&mut self, tmp: &[u8]) -> bool {
+        let ec = self.ec;
+        let g1_blocks = self.version.g1_blocks(ec);
+        let g2_blocks = self.version.g2_blocks(ec);
+        let g1_blk_size = self.version.g1_blk_size(ec);
+        let g2_blk_size = g1_blk_size + 1;
+        let ec_size = self.version.ec_size(ec);
+        let blocks = g1_blocks + g2_blocks;
+        let data_len = g1_blocks * g1_blk_size + g2_blocks * g2_blk_size;
+        let total = data_len + ec_size * blocks;
+
+        // Round-trip: the drawn modules must decode to the codewords in tmp.
+        self.x = self.width - 2;
+        self.y = self.width;
+        for k in 0..total {
+            let off =
+                interleaved_offset(k, g1_blocks, g2_blocks, g1_blk_size, g2_blk_size, ec_size);
+            if self.read_byte() != tmp[off] {
+                return false;
+            }
+        }
+
+        // Reed-Solomon: every block must have zero syndromes.
+        let mut offset = 0;
+        let mut ec_offset = data_len;
+        for blk in 0..blocks {
+            let size = if blk < g1_blocks {
+                g1_blk_size
+            } else {
+                g2_blk_size
+            };
+            if !syndromes_zero(tmp, offset, size, ec_offset, ec_size) {
+                return false;
+            }
+            offset += size;
+            ec_offset += ec_size;
+        }
+        true
+    }
+}
+
+/// Smallest version and its optimal segmentation for `data`.
+///
+/// The per-character cost depends on the count-field width, which only changes
+/// at the version-class boundaries (1-9, 10-26, 27-40). Optimize once per
+/// class, using the class's first version as the cost reference, and keep the
+/// first segmentation whose best-fit version lands in (or below) that class.
+/// On success `segs` holds the chosen segments and the returned count says how
+/// many are valid.
+fn optimize_for_version<'a>(
+    data: &'a [u8],
+    ec: EcLevel,
+    segs: &mut [Segment<'a>],
+) -> Option<(Version, usize)> {
+    for (hi, ref_v) in [(9usize, Version(1)), (26, Version(10)), (40, Version(27))] {
+        let n = Segment::optimize(data, ref_v, segs);
+        let mut refs: [&Segment<'_>; MAX_SEGMENTS] = [&segs[0]; MAX_SEGMENTS];
+        for k in 0..n {
+            refs[k] = &segs[k];
+        }
+        if let Some(v) = Version::from_segments(&refs[..n], ec) {
+            if v.0 <= hi {
+                return Some((v, n));
+            }
+        }
+    }
+    None
 }
 
 /// qr_encode_txt, the main entry point to generate a qrcode with text.
-/// data: ascii text data, that will be encoded in a binary segment.
+/// data: ascii text data, split into the cheapest mix of alphanumeric and
+/// binary segments, or, when too big for one code, cut into chunks that each
+/// take one binary
 /// segment. The length of this slice is the total length of the buffer, and
 /// should be at least 4071 bytes to hold a V40 QR-code.
 /// data will be overwritten with the QR-code image.
 /// data_len: length of the binary data, put in the data slice.
 /// tmp: a temporary slice that the QR-code encoder will use, to write the
 /// segments data and ECC. It must be at least 3706 bytes long (for V40)
+/// ec: the error correction level to use.
+/// symbol: the index of the symbol to render. When the input does not fit in a
+/// single QR code it is split across a structured-append sequence; the panic
+/// handler renders `symbol` 0, 1, ... in turn until this returns 0.
+/// Since `data` is overwritten in place with the rendered image, the caller
+/// must rewrite `data[0..data_len]` with the original payload before every
+/// call past the first one in a structured-append sequence; this function
+/// re-reads that range on each call to chunk out the symbol's payload, and
+/// has no spare buffer to stash a copy of its own.
 ///
 /// returns the size of the QR code, 21 for V1, 177 for V40 or 0 in case of
 /// failure
 
-fn qr_encode_txt(data: &mut [u8], data_len: usize, tmp: &mut [u8]) -> Result<u8, ()> {
-    let seg_data = Segment::Binary(&data[0..data_len]);
+fn qr_encode_txt(
+    data: &mut [u8],
+    data_len: usize,
+    tmp: &mut [u8],
+    ec: EcLevel,
+    symbol: u8,
+) -> Result<u8, ()> {
+    // Does the whole input fit in a single code? If so, a structured-append
+    // header is unnecessary and only symbol 0 exists.
+    {
+        let mut segs = [Segment::Binary(&[]); MAX_SEGMENTS];
+        if let Some((version, n)) = optimize_for_version(&data[0..data_len], ec, &mut segs) {
+            if symbol != 0 {
+                return Ok(0);
+            }
+            let mut refs: [&Segment<'_>; MAX_SEGMENTS] = [&segs[0]; MAX_SEGMENTS];
+            for k in 0..n {
+                refs[k] = &segs[k];
+            }
 
-    let version = Version::from_segments(&[&seg_data]).ok_or(())?;
+            let mut em = EncodedMsg::init(version, ec, tmp);
+            em.encode(&refs[..n]);
 
-    let mut em = EncodedMsg::init(version, tmp);
-    em.encode(&[&seg_data]);
+            let mut qr_code = QrImage::init(version, ec, data);
+            let width = qr_code.draw_all(em);
+            #[cfg(debug_assertions)]
+            if !qr_code.verify(tmp) {
+                return Err(());
+            }
+            return Ok(width);
+        }
+    }
+
+    // Too big for one code: span it with a structured-append sequence of
+    // binary chunks, using the largest version to keep the symbol count low.
+    let version = Version(40);
+    let parity = data[0..data_len].iter().fold(0u8, |p, &b| p ^ b);
+    let overhead = STRUCTURED_APPEND_BITS + 4 + Segment::Binary(&[]).length_bits_count(version);
+    if version.max_data(ec) * 8 <= overhead {
+        return Err(());
+    }
+    let chunk = (version.max_data(ec) * 8 - overhead) / 8;
+    let count = data_len.div_ceil(chunk);
+    if count == 0 || count > MAX_STRUCTURED_SYMBOLS {
+        return Err(());
+    }
+    if symbol as usize >= count {
+        return Ok(0);
+    }
 
-    let mut qr_code = QrImage::init(version, data);
-    Ok(qr_code.draw_all(em))
+    let start = symbol as usize * chunk;
+    let end = cmp::min(start + chunk, data_len);
+    let mut em = EncodedMsg::init(version, ec, tmp);
+    {
+        let seg = Segment::Binary(&data[start..end]);
+        em.encode_structured(symbol, count as u8, parity, &seg);
+    }
+    let mut qr_code = QrImage::init(version, ec, data);
+    let width = qr_code.draw_all(em);
+    #[cfg(debug_assertions)]
+    if !qr_code.verify(tmp) {
+        return Err(());
+    }
+    Ok(width)
 }
 
 /// qr_encode_url, the main entry point to generate a qrcode.
-/// url: the base url of the QR code. will be encoded as Binary segment.
+/// url: the base url of the QR code. Encoded with the optimal mix of
+/// alphanumeric and binary segments when everything fits in one code, or as
+/// a single binary segment on the first symbol of a structured-append
+/// sequence.
 /// data: binary data, appended to url, will be encoded efficiently as Numeric
 /// segment. The length of this slice is the total length of the buffer, and
 /// should be at least 4071 bytes to hold a V40 QR-code.
@@ -931,21 +1720,186 @@ fn qr_encode_txt(data: &mut [u8], data_len: usize, tmp: &mut [u8]) -> Result<u8,
 /// data_len: length of the binary data, put in the data slice.
 /// tmp: a temporary slice that the QR-code encoder will use, to write the
 /// segments data and ECC. It must be at least 3706 bytes long (for V40)
+/// ec: the error correction level to use.
+/// symbol: the index of the symbol to render. When the input does not fit in a
+/// single QR code it is split across a structured-append sequence; the panic
+/// handler renders `symbol` 0, 1, ... in turn until this returns 0.
+/// Since `data` is overwritten in place with the rendered image, the caller
+/// must rewrite `data[0..data_len]` with the original payload before every
+/// call past the first one in a structured-append sequence; this function
+/// re-reads that range on each call to chunk out the symbol's payload, and
+/// has no spare buffer to stash a copy of its own.
 ///
 /// returns the size of the QR code, 21 for V1, 177 for V40 or 0 in case of
 /// failure
 
-fn qr_encode_url(url: &str, data: &mut [u8], data_len: usize, tmp: &mut [u8]) -> Result<u8, ()> {
-    let seg_url = Segment::Binary(url.as_bytes());
+fn qr_encode_url(
+    url: &str,
+    data: &mut [u8],
+    data_len: usize,
+    tmp: &mut [u8],
+    ec: EcLevel,
+    symbol: u8,
+) -> Result<u8, ()> {
+    let url_bytes = url.as_bytes();
+    if let Some(width) = qr_encode_url_single(url_bytes, data, data_len, tmp, ec, symbol)? {
+        return Ok(width);
+    }
+    qr_encode_url_structured(url_bytes, data, data_len, tmp, ec, symbol)
+}
+
+/// Encode url + payload in a single QR code. Returns `Some(width)` when it
+/// fits (and `symbol` is 0), `Some(0)` when it fits but `symbol` is past the
+/// only symbol, or `None` when the input needs a structured-append sequence.
+fn qr_encode_url_single(
+    url_bytes: &[u8],
+    data: &mut [u8],
+    data_len: usize,
+    tmp: &mut [u8],
+    ec: EcLevel,
+    symbol: u8,
+) -> Result<Option<u8>, ()> {
     let seg_data = Segment::Numeric(&data[0..data_len]);
 
-    let version = Version::from_segments(&[&seg_url, &seg_data]).ok_or(())?;
+    // Optimize the url prefix (it may carry uppercase/alphanumeric runs), but
+    // keep the compressed payload in the denser custom numeric segment. The
+    // count-field width only changes at the version-class boundaries, so try
+    // each class in turn and keep the first fit.
+    let mut url_segs = [Segment::Binary(&[]); MAX_SEGMENTS];
+    let mut found: Option<(Version, usize)> = None;
+    for (hi, ref_v) in [(9usize, Version(1)), (26, Version(10)), (40, Version(27))] {
+        let n = Segment::optimize(url_bytes, ref_v, &mut url_segs);
+        let mut refs: [&Segment<'_>; MAX_SEGMENTS + 1] = [&seg_data; MAX_SEGMENTS + 1];
+        for k in 0..n {
+            refs[k] = &url_segs[k];
+        }
+        refs[n] = &seg_data;
+        if let Some(v) = Version::from_segments(&refs[..n + 1], ec) {
+            if v.0 <= hi {
+                found = Some((v, n));
+                break;
+            }
+        }
+    }
+    // Does not fit in one code: defer to the structured-append path.
+    let Some((version, n)) = found else {
+        return Ok(None);
+    };
+    if symbol != 0 {
+        return Ok(Some(0));
+    }
+
+    let mut refs: [&Segment<'_>; MAX_SEGMENTS + 1] = [&seg_data; MAX_SEGMENTS + 1];
+    for k in 0..n {
+        refs[k] = &url_segs[k];
+    }
+    refs[n] = &seg_data;
 
-    let mut em = EncodedMsg::init(version, tmp);
-    em.encode(&[&seg_url, &seg_data]);
+    let mut em = EncodedMsg::init(version, ec, tmp);
+    em.encode(&refs[..n + 1]);
 
-    let mut qr_code = QrImage::init(version, data);
-    Ok(qr_code.draw_all(em))
+    let mut qr_code = QrImage::init(version, ec, data);
+    let width = qr_code.draw_all(em);
+    #[cfg(debug_assertions)]
+    if !qr_code.verify(tmp) {
+        return Err(());
+    }
+    Ok(Some(width))
+}
+
+/// Longest prefix of `data` whose Numeric segment (header + length field +
+/// packed digits) fits within `budget_bits` at `version`. `total_size_bits`
+/// depends only on the prefix length, so a binary search over it suffices.
+fn numeric_fit(data: &[u8], budget_bits: usize, version: Version) -> usize {
+    let mut lo = 0usize;
+    let mut hi = data.len();
+    while lo < hi {
+        let mid = lo + (hi - lo + 1) / 2;
+        if Segment::Numeric(&data[..mid]).total_size_bits(version) <= budget_bits {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    lo
+}
+
+/// Span url + payload across a structured-append sequence when they do not fit
+/// in a single code. The url travels on symbol 0; the payload is chunked as
+/// Numeric segments, same as the single-code path, so splitting across
+/// symbols keeps the payload's numeric density. The
+/// largest version is used to keep the symbol count low. Returns the rendered
+/// width, or 0 when `symbol` is past the end of the sequence.
+fn qr_encode_url_structured(
+    url_bytes: &[u8],
+    data: &mut [u8],
+    data_len: usize,
+    tmp: &mut [u8],
+    ec: EcLevel,
+    symbol: u8,
+) -> Result<u8, ()> {
+    let version = Version(40);
+    let parity = url_bytes
+        .iter()
+        .chain(data[0..data_len].iter())
+        .fold(0u8, |p, &b| p ^ b);
+
+    let avail = version.max_data(ec) * 8;
+    let url_bits = Segment::Binary(url_bytes).total_size_bits(version);
+    // Payload bytes per symbol: symbol 0 also carries the url segment.
+    if avail <= STRUCTURED_APPEND_BITS + url_bits || avail <= STRUCTURED_APPEND_BITS {
+        return Err(());
+    }
+    let chunk0 = numeric_fit(
+        &data[0..data_len],
+        avail - STRUCTURED_APPEND_BITS - url_bits,
+        version,
+    );
+    let chunk = numeric_fit(&data[0..data_len], avail - STRUCTURED_APPEND_BITS, version);
+    if chunk == 0 {
+        return Err(());
+    }
+
+    let count = if data_len <= chunk0 {
+        1
+    } else {
+        1 + (data_len - chunk0).div_ceil(chunk)
+    };
+    if count > MAX_STRUCTURED_SYMBOLS {
+        return Err(());
+    }
+    if symbol as usize >= count {
+        return Ok(0);
+    }
+
+    let (start, end) = if symbol == 0 {
+        (0, cmp::min(chunk0, data_len))
+    } else {
+        let s = chunk0 + (symbol as usize - 1) * chunk;
+        (s, cmp::min(s + chunk, data_len))
+    };
+
+    let mut em = EncodedMsg::init(version, ec, tmp);
+    {
+        let seg_pay = Segment::Numeric(&data[start..end]);
+        if symbol == 0 {
+            let seg_url = Segment::Binary(url_bytes);
+            em.add_structured_append(0, count as u8, parity);
+            em.add_segment(&seg_url);
+            em.add_segment(&seg_pay);
+            em.finish();
+            em.compute_error_code();
+        } else {
+            em.encode_structured(symbol, count as u8, parity, &seg_pay);
+        }
+    }
+    let mut qr_code = QrImage::init(version, ec, data);
+    let width = qr_code.draw_all(em);
+    #[cfg(debug_assertions)]
+    if !qr_code.verify(tmp) {
+        return Err(());
+    }
+    Ok(width)
 }
 
 ///
@@ -954,6 +1908,18 @@ fn qr_encode_url(url: &str, data: &mut [u8], data_len: usize, tmp: &mut [u8]) ->
 /// C entry point for the rust QR Code generator
 ///
 /// return the qrcode size, or 0 if the data is too big and can't fit in a QR-code
+///
+/// ec_level selects the error correction level: 0 for Low, 1 for Medium, 2 for
+/// Quartile and 3 for High. Higher levels are more robust to a damaged or
+/// glare-covered screen, at the cost of capacity.
+///
+/// symbol selects which code of the structured-append sequence to render when
+/// the data is too big for one code. The caller renders symbol 0, 1, ... in
+/// turn until this returns 0. `data` is overwritten in place with each
+/// rendered image, so on a structured-append sequence the caller must
+/// rewrite `data[0..data_len]` with the original payload before every call
+/// past the first (no C caller in this tree exercises that path; wire it up
+/// before relying on it).
 #[no_mangle]
 pub extern "C" fn drm_panic_qr_generate(
     url: *const i8,
@@ -962,14 +1928,17 @@ pub extern "C" fn drm_panic_qr_generate(
     data_size: usize,
     tmp: *mut u8,
     tmp_size: usize,
+    ec_level: u8,
+    symbol: u8,
 ) -> u8 {
     let data_slice = unsafe { core::slice::from_raw_parts_mut(data, data_size) };
     let tmp_slice = unsafe { core::slice::from_raw_parts_mut(tmp, tmp_size) };
+    let ec = EcLevel::from_u8(ec_level);
     if url.is_null() {
-        qr_encode_txt(data_slice, data_len, tmp_slice).unwrap_or(0)
+        qr_encode_txt(data_slice, data_len, tmp_slice, ec, symbol).unwrap_or(0)
     } else {
         // Safety, url is known at build time
         let url_str = unsafe { CStr::from_char_ptr(url).as_str_unchecked() };
-        qr_encode_url(url_str, data_slice, data_len, tmp_slice).unwrap_or(0)
+        qr_encode_url(url_str, data_slice, data_len, tmp_slice, ec, symbol).unwrap_or(0)
     }
 }